@@ -1,13 +1,21 @@
 // An abstract layer towards ncurses-rs, which provides keycode, color scheme support
 // Modeled after fzf
+//
+// NOTE: init_extended_color/init_extended_pair below need the ncurses crate's "wide"
+// feature enabled in Cargo.toml (this tree has no Cargo.toml to edit yet).
 
 use ncurses::*;
 use getopts;
 use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
-use libc::{STDIN_FILENO, STDERR_FILENO, fdopen, c_char};
+use libc::{STDIN_FILENO, STDERR_FILENO, fdopen, c_char, c_int, SIGWINCH};
 use std::io::{stdout, stdin, Read, Write};
 use std::cmp::min;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use toml;
 
 //use std::io::Write;
 macro_rules! println_stderr(
@@ -34,6 +42,110 @@ lazy_static! {
     static ref FG: RwLock<i16> = RwLock::new(7);
     static ref BG: RwLock<i16> = RwLock::new(0);
     static ref USE_COLOR: RwLock<bool> = RwLock::new(true);
+    static ref RGB_COLOR_MAP: RwLock<HashMap<(u8, u8, u8), i16>> = RwLock::new(HashMap::new());
+    static ref NEXT_EXT_COLOR: RwLock<i16> = RwLock::new(256);
+}
+
+// whether the terminal can take arbitrary 24-bit colors, as opposed to a fixed palette
+fn supports_truecolor() -> bool {
+    tigetnum("colors") >= 16_777_216 ||
+        env::var("COLORTERM").map(|v| v == "truecolor" || v == "24bit").unwrap_or(false)
+}
+
+// allocate (or reuse) an extended color slot for an arbitrary RGB value
+fn alloc_extended_color(r: u8, g: u8, b: u8) -> i16 {
+    let mut rgb_map = RGB_COLOR_MAP.write().unwrap();
+    if let Some(&idx) = rgb_map.get(&(r, g, b)) {
+        return idx;
+    }
+
+    let mut next = NEXT_EXT_COLOR.write().unwrap();
+    let idx = *next;
+    *next += 1;
+
+    let scale = |c: u8| (c as i32) * 1000 / 255;
+    init_extended_color(idx as i32, scale(r), scale(g), scale(b));
+    rgb_map.insert((r, g, b), idx);
+    idx
+}
+
+// downsample an RGB value to the nearest index in the 256-color palette, preferring
+// whichever of the 6x6x6 color cube or the 24-step gray ramp is closer
+fn downsample_256(r: u8, g: u8, b: u8) -> i16 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    let nearest_level = |c: u8| -> usize {
+        LEVELS.iter()
+            .enumerate()
+            .min_by_key(|&(_, &l)| (l as i32 - c as i32).abs())
+            .map(|(i, _)| i)
+            .unwrap()
+    };
+
+    let (ri, gi, bi) = (nearest_level(r), nearest_level(g), nearest_level(b));
+    let cube_index = 16 + 36 * ri as i16 + 6 * gi as i16 + bi as i16;
+    let cube_rgb = (LEVELS[ri], LEVELS[gi], LEVELS[bi]);
+
+    let luma = (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64).round() as i32;
+    let gray_step = ((luma - 8 + 5).max(0) / 10).min(23);
+    let gray_index = 232 + gray_step as i16;
+    let gray_level = (8 + gray_step * 10) as u8;
+
+    let dist = |(cr, cg, cb): (u8, u8, u8)| -> i32 {
+        let (dr, dg, db) = (cr as i32 - r as i32, cg as i32 - g as i32, cb as i32 - b as i32);
+        dr * dr + dg * dg + db * db
+    };
+
+    if dist(cube_rgb) <= dist((gray_level, gray_level, gray_level)) {
+        cube_index
+    } else {
+        gray_index
+    }
+}
+
+// whether a single candidate color is representable on the current terminal
+fn color_is_representable(color: Color) -> bool {
+    match color {
+        Color::Undefined => false,
+        Color::Default => true,
+        Color::Palette(n) => (n as i32) < tigetnum("colors"),
+        Color::Rgb(..) => supports_truecolor() || tigetnum("colors") >= 256,
+    }
+}
+
+// resolve a single theme color down to the palette index ncurses should actually be
+// given, allocating an extended color or downsampling to 256 colors as the terminal allows
+fn resolve_color(color: Color) -> i16 {
+    match color {
+        Color::Undefined => COLOR_UNDEFINED,
+        Color::Default => COLOR_DEFAULT,
+        Color::Palette(n) => n,
+        Color::Rgb(r, g, b) => {
+            if supports_truecolor() {
+                alloc_extended_color(r, g, b)
+            } else if tigetnum("colors") >= 256 {
+                downsample_256(r, g, b)
+            } else {
+                COLOR_UNDEFINED
+            }
+        }
+    }
+}
+
+// walk a chain's candidates and pick the first the terminal can represent, falling
+// back to the base theme's chain for this role if none of the theme's own resolve
+fn resolve_chain(base: ColorChain, theme: ColorChain) -> i16 {
+    let resolve = |chain: ColorChain| chain.iter().copied().find(|&c| color_is_representable(c)).map(resolve_color);
+    resolve(theme).or_else(|| resolve(base)).unwrap_or(COLOR_UNDEFINED)
+}
+
+// like init_pair, but goes through init_extended_pair when the terminal is truecolor
+fn set_pair(pair: i16, fg: i16, bg: i16) {
+    if supports_truecolor() {
+        init_extended_pair(pair as i32, fg as i32, bg as i32);
+    } else {
+        init_pair(pair, fg, bg);
+    }
 }
 
 pub fn init(theme: Option<&ColorTheme>, is_black: bool, _use_mouse: bool) {
@@ -59,8 +171,8 @@ fn init_pairs(base: &ColorTheme, theme: &ColorTheme, is_black: bool) {
     let mut bg = BG.write().unwrap();
 
 
-    *fg = shadow(base.fg, theme.fg);
-    *bg = shadow(base.bg, theme.bg);
+    *fg = resolve_chain(base.fg, theme.fg);
+    *bg = resolve_chain(base.bg, theme.bg);
 
     if is_black {
         *bg = COLOR_BLACK;
@@ -71,20 +183,20 @@ fn init_pairs(base: &ColorTheme, theme: &ColorTheme, is_black: bool) {
     }
 
     if !theme.use_default {
-        assume_default_colors(shadow(base.fg, theme.fg) as i32, shadow(base.bg, theme.bg) as i32);
+        assume_default_colors(*fg as i32, *bg as i32);
     }
 
     start_color();
 
-    init_pair(COLOR_PROMPT,        shadow(base.prompt,        theme.prompt),        *bg);
-    init_pair(COLOR_MATCHED,       shadow(base.matched,       theme.matched),       shadow(base.matched_bg, theme.matched_bg));
-    init_pair(COLOR_CURRENT,       shadow(base.current,       theme.current),       shadow(base.current_bg, theme.current_bg));
-    init_pair(COLOR_CURRENT_MATCH, shadow(base.current_match, theme.current_match), shadow(base.current_match_bg, theme.current_match_bg));
-    init_pair(COLOR_SPINNER,       shadow(base.spinner,       theme.spinner),       *bg);
-    init_pair(COLOR_INFO,          shadow(base.info,          theme.info),          *bg);
-    init_pair(COLOR_CURSOR,        shadow(base.cursor,        theme.cursor),        shadow(base.current_bg, theme.current_bg));
-    init_pair(COLOR_SELECTED,      shadow(base.selected,      theme.selected),      shadow(base.current_bg, theme.current_bg));
-    init_pair(COLOR_HEADER,        shadow(base.header,        theme.header),        shadow(base.bg, theme.bg));
+    set_pair(COLOR_PROMPT,        resolve_chain(base.prompt,        theme.prompt),        *bg);
+    set_pair(COLOR_MATCHED,       resolve_chain(base.matched,       theme.matched),       resolve_chain(base.matched_bg, theme.matched_bg));
+    set_pair(COLOR_CURRENT,       resolve_chain(base.current,       theme.current),       resolve_chain(base.current_bg, theme.current_bg));
+    set_pair(COLOR_CURRENT_MATCH, resolve_chain(base.current_match, theme.current_match), resolve_chain(base.current_match_bg, theme.current_match_bg));
+    set_pair(COLOR_SPINNER,       resolve_chain(base.spinner,       theme.spinner),       *bg);
+    set_pair(COLOR_INFO,          resolve_chain(base.info,          theme.info),          *bg);
+    set_pair(COLOR_CURSOR,        resolve_chain(base.cursor,        theme.cursor),        resolve_chain(base.current_bg, theme.current_bg));
+    set_pair(COLOR_SELECTED,      resolve_chain(base.selected,      theme.selected),      resolve_chain(base.current_bg, theme.current_bg));
+    set_pair(COLOR_HEADER,        resolve_chain(base.header,        theme.header),        resolve_chain(base.bg, theme.bg));
 }
 
 
@@ -97,7 +209,7 @@ pub fn get_color_pair(fg: i16, bg: i16) -> attr_t {
     let pair_num = color_map.len() as i16;
     let pair = color_map.entry(key).or_insert_with(|| {
         let next_pair = COLOR_USER + pair_num;
-        init_pair(next_pair, fg, bg);
+        set_pair(next_pair, fg, bg);
         COLOR_PAIR(next_pair)
     });
     *pair
@@ -123,48 +235,145 @@ pub enum Margin {
 // |
 // |
 
-struct Screen(SCREEN);
+// The drawing primitives Curses issues, abstracted so it can run against a real
+// terminal (NcursesBackend) or an in-memory grid (VirtualBackend, for tests).
+pub trait Backend {
+    fn mv(&mut self, y: i32, x: i32);
+    fn print(&mut self, text: &str);
+    fn get_maxyx(&self) -> (i32, i32);
+    fn getyx(&self) -> (i32, i32);
+    fn attr_on(&mut self, attr: attr_t);
+    fn attr_off(&mut self, attr: attr_t);
+    fn refresh(&mut self);
+}
 
-impl Screen {
-    pub fn getyx(&self) -> (i32, i32) {
-        let mut y = 0;
-        let mut x = 0;
-        getyx(self.0, &mut y, &mut x);
-        (y, x)
+// Holds the real ncurses SCREEN so it can be torn down with endwin/delscreen.
+pub struct NcursesBackend(SCREEN);
+
+impl NcursesBackend {
+    fn new(screen: SCREEN) -> Self {
+        NcursesBackend(screen)
+    }
+
+    fn endwin(&self) {
+        endwin();
+    }
+
+    fn delscreen(&self) {
+        delscreen(self.0);
+    }
+}
+
+impl Backend for NcursesBackend {
+    fn mv(&mut self, y: i32, x: i32) {
+        mv(y, x);
     }
 
-    pub fn getmaxyx(&self) -> (i32, i32) {
+    fn print(&mut self, text: &str) {
+        addstr(text);
+    }
+
+    fn get_maxyx(&self) -> (i32, i32) {
         let mut max_y = 0;
         let mut max_x = 0;
         getmaxyx(self.0, &mut max_y, &mut max_x);
         (max_y, max_x)
     }
 
-    pub fn clrtoeol(&self) {
-        clrtoeol();
+    fn getyx(&self) -> (i32, i32) {
+        let mut y = 0;
+        let mut x = 0;
+        getyx(self.0, &mut y, &mut x);
+        (y, x)
     }
 
-    pub fn endwin(&self) {
-        endwin();
+    fn attr_on(&mut self, attr: attr_t) {
+        attron(attr);
     }
 
-    pub fn refresh(&self) {
+    fn attr_off(&mut self, attr: attr_t) {
+        attroff(attr);
+    }
+
+    fn refresh(&mut self) {
         refresh();
     }
+}
 
-    pub fn mv(&self, y: i32, x: i32) {
-        mv(y, x);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub attr: attr_t,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell { ch: ' ', attr: 0 }
     }
 }
 
-impl Drop for Screen {
-    fn drop(&mut self) {
-        //delscreen(self.0);
+// Records every draw call into an in-memory grid of cells instead of a real terminal.
+pub struct VirtualBackend {
+    grid: Vec<Vec<Cell>>,
+    cursor: (i32, i32),
+    attr: attr_t,
+}
+
+impl VirtualBackend {
+    pub fn new(rows: i32, cols: i32) -> Self {
+        VirtualBackend {
+            grid: vec![vec![Cell::default(); cols.max(0) as usize]; rows.max(0) as usize],
+            cursor: (0, 0),
+            attr: 0,
+        }
+    }
+
+    pub fn cell(&self, y: i32, x: i32) -> Cell {
+        self.grid[y as usize][x as usize]
     }
+
+    pub fn row(&self, y: i32) -> String {
+        self.grid[y as usize].iter().map(|cell| cell.ch).collect()
+    }
+}
+
+impl Backend for VirtualBackend {
+    fn mv(&mut self, y: i32, x: i32) {
+        self.cursor = (y, x);
+    }
+
+    fn print(&mut self, text: &str) {
+        let (y, mut x) = self.cursor;
+        for ch in text.chars() {
+            if y >= 0 && x >= 0 && (y as usize) < self.grid.len() && (x as usize) < self.grid[y as usize].len() {
+                self.grid[y as usize][x as usize] = Cell { ch, attr: self.attr };
+            }
+            x += 1;
+        }
+        self.cursor = (y, x);
+    }
+
+    fn get_maxyx(&self) -> (i32, i32) {
+        (self.grid.len() as i32, self.grid.first().map_or(0, |row| row.len() as i32))
+    }
+
+    fn getyx(&self) -> (i32, i32) {
+        self.cursor
+    }
+
+    fn attr_on(&mut self, attr: attr_t) {
+        self.attr |= attr;
+    }
+
+    fn attr_off(&mut self, attr: attr_t) {
+        self.attr &= !attr;
+    }
+
+    fn refresh(&mut self) {}
 }
 
-pub struct Curses {
-    screen: Screen,
+pub struct Curses<B: Backend = NcursesBackend> {
+    backend: B,
     top: i32,
     bottom: i32,
     left: i32,
@@ -175,25 +384,39 @@ pub struct Curses {
     margin_bottom: Margin,
     margin_left: Margin,
     margin_right: Margin,
+
+    // double-buffered cell grid: draw calls land in `back`, commit() diffs it
+    // against `front` and only redraws what changed
+    front: Vec<Vec<Cell>>,
+    back: Vec<Vec<Cell>>,
+    cursor: (i32, i32),
+    cur_attr: attr_t,
 }
 
-unsafe impl Send for Curses {}
+unsafe impl<B: Backend> Send for Curses<B> {}
+
+// whether a SIGWINCH has arrived since the last `Curses::resize_pending()` check
+static RESIZE_PENDING: AtomicBool = AtomicBool::new(false);
 
-impl Curses {
+extern "C" fn on_sigwinch(_signum: c_int) {
+    RESIZE_PENDING.store(true, Ordering::SeqCst);
+}
+
+impl Curses<NcursesBackend> {
     pub fn new(options: &getopts::Matches) -> Self {
         let local_conf = LcCategory::all;
         setlocale(local_conf, "en_US.UTF-8"); // for showing wide characters
 
 
         let margins = if let Some(margin_option) = options.opt_str("margin") {
-            Curses::parse_margin(&margin_option)
+            Curses::<NcursesBackend>::parse_margin(&margin_option)
         } else {
             (Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0))
         };
         let (margin_top, margin_right, margin_bottom, margin_left) = margins;
 
         let height = if let Some(height_option) = options.opt_str("height") {
-            Curses::parse_margin_string(&height_option)
+            Curses::<NcursesBackend>::parse_margin_string(&height_option)
         } else {
             Margin::Percent(100)
         };
@@ -208,7 +431,7 @@ impl Curses {
         raw();
         noecho();
 
-        let screen = Screen(s);
+        let backend = NcursesBackend::new(s);
 
         match height {
             Margin::Percent(100) => {}
@@ -218,9 +441,9 @@ impl Curses {
             }
         };
 
-        let (y, x) = Curses::get_cursor_pos();
-        let (max_y, max_x) = screen.getmaxyx();
-        Curses::reserve_lines(&screen, max_y, height);
+        let (y, x) = Curses::<NcursesBackend>::get_cursor_pos();
+        let (max_y, max_x) = backend.get_maxyx();
+        Curses::<NcursesBackend>::reserve_lines(max_y, height);
 
         let start_y = match height {
             Margin::Percent(100) => 0,
@@ -230,8 +453,10 @@ impl Curses {
 
         debug!("curses: height = {:?}, y/x: {}/{}, max: {}/{}, start_y: {}", height, y, x, max_y, max_x, start_y);
 
+        Curses::<NcursesBackend>::install_resize_handler();
+
         let mut curses = Curses {
-            screen: screen,
+            backend,
             top: 0,
             bottom: 0,
             left: 0,
@@ -242,54 +467,19 @@ impl Curses {
             margin_bottom,
             margin_left,
             margin_right,
+            front: Vec::new(),
+            back: Vec::new(),
+            cursor: (0, 0),
+            cur_attr: 0,
         };
         curses.resize();
         curses
     }
 
-    fn parse_margin_string(margin: &str) -> Margin {
-        if margin.ends_with("%") {
-            Margin::Percent(margin[0..margin.len()-1].parse::<i32>().unwrap_or(100))
-        } else {
-            Margin::Fixed(margin.parse::<i32>().unwrap_or(0))
-        }
-    }
-
-    fn parse_margin(margin : &str) -> (Margin, Margin, Margin, Margin) {
-        let margins = margin.split(",").collect::<Vec<&str>>();
-
-        match margins.len() {
-            1 => {
-                let margin = Curses::parse_margin_string(margins[0]);
-                (margin, margin, margin, margin)
-            }
-            2 => {
-                let margin_tb = Curses::parse_margin_string(margins[0]);
-                let margin_rl = Curses::parse_margin_string(margins[1]);
-                (margin_tb, margin_rl, margin_tb, margin_rl)
-            }
-            3 => {
-                let margin_top = Curses::parse_margin_string(margins[0]);
-                let margin_rl = Curses::parse_margin_string(margins[1]);
-                let margin_bottom = Curses::parse_margin_string(margins[2]);
-                (margin_top, margin_rl, margin_bottom, margin_rl)
-            }
-            4 => {
-                let margin_top = Curses::parse_margin_string(margins[0]);
-                let margin_right = Curses::parse_margin_string(margins[1]);
-                let margin_bottom = Curses::parse_margin_string(margins[2]);
-                let margin_left = Curses::parse_margin_string(margins[3]);
-                (margin_top, margin_right, margin_bottom, margin_left)
-            }
-            _ => (Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0))
-        }
-    }
-
-    fn get_color(&self, pair: i16, is_bold: bool) -> attr_t {
-        if *USE_COLOR.read().unwrap() {
-            attr_color(pair, is_bold)
-        } else {
-            attr_mono(pair, is_bold)
+    // install a SIGWINCH handler so resize_pending() can tell the caller to repaint
+    fn install_resize_handler() {
+        unsafe {
+            libc::signal(SIGWINCH, on_sigwinch as *const () as libc::sighandler_t);
         }
     }
 
@@ -313,7 +503,7 @@ impl Curses {
         (t[0].parse::<i32>().unwrap() - 1, t[1].parse::<i32>().unwrap() - 1)
     }
 
-    fn reserve_lines(screen: &Screen, max_y: i32, height: Margin) {
+    fn reserve_lines(max_y: i32, height: Margin) {
         let rows = match height {
             Margin::Percent(100) => {return;}
             Margin::Percent(percent) => max_y*percent/100,
@@ -330,8 +520,114 @@ impl Curses {
         refresh();
     }
 
+    pub fn close(&mut self) {
+        debug!("curses:close();");
+        self.erase();
+        self.mv(0, 0);
+        self.commit();
+        if self.height != Margin::Percent(100) {
+            putp(&tigetstr("smcup"));
+            refresh();
+        }
+        self.backend.endwin();
+        self.backend.delscreen();
+    }
+}
+
+impl<B: Backend> Curses<B> {
+    // build a Curses bound to an arbitrary backend, skipping the real-terminal setup
+    // (initscr, cursor position probing, alternate screen) that `new` does
+    pub fn with_backend(backend: B, margin_option: Option<&str>, height_option: Option<&str>) -> Self {
+        let margins = margin_option.map(Curses::<B>::parse_margin)
+            .unwrap_or((Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0)));
+        let (margin_top, margin_right, margin_bottom, margin_left) = margins;
+
+        let height = height_option.map(Curses::<B>::parse_margin_string).unwrap_or(Margin::Percent(100));
+
+        let mut curses = Curses {
+            backend,
+            top: 0,
+            bottom: 0,
+            left: 0,
+            right: 0,
+            height,
+            start_y: 0,
+            margin_top,
+            margin_bottom,
+            margin_left,
+            margin_right,
+            front: Vec::new(),
+            back: Vec::new(),
+            cursor: (0, 0),
+            cur_attr: 0,
+        };
+        curses.resize();
+        curses
+    }
+
+    // whether a SIGWINCH has arrived since the last call; follow up with handle_resize()
+    pub fn resize_pending() -> bool {
+        RESIZE_PENDING.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn handle_resize(&mut self) {
+        self.resize();
+    }
+
+    fn parse_margin_string(margin: &str) -> Margin {
+        if margin.ends_with("%") {
+            Margin::Percent(margin[0..margin.len()-1].parse::<i32>().unwrap_or(100))
+        } else {
+            Margin::Fixed(margin.parse::<i32>().unwrap_or(0))
+        }
+    }
+
+    fn parse_margin(margin : &str) -> (Margin, Margin, Margin, Margin) {
+        let margins = margin.split(",").collect::<Vec<&str>>();
+
+        match margins.len() {
+            1 => {
+                let margin = Curses::<B>::parse_margin_string(margins[0]);
+                (margin, margin, margin, margin)
+            }
+            2 => {
+                let margin_tb = Curses::<B>::parse_margin_string(margins[0]);
+                let margin_rl = Curses::<B>::parse_margin_string(margins[1]);
+                (margin_tb, margin_rl, margin_tb, margin_rl)
+            }
+            3 => {
+                let margin_top = Curses::<B>::parse_margin_string(margins[0]);
+                let margin_rl = Curses::<B>::parse_margin_string(margins[1]);
+                let margin_bottom = Curses::<B>::parse_margin_string(margins[2]);
+                (margin_top, margin_rl, margin_bottom, margin_rl)
+            }
+            4 => {
+                let margin_top = Curses::<B>::parse_margin_string(margins[0]);
+                let margin_right = Curses::<B>::parse_margin_string(margins[1]);
+                let margin_bottom = Curses::<B>::parse_margin_string(margins[2]);
+                let margin_left = Curses::<B>::parse_margin_string(margins[3]);
+                (margin_top, margin_right, margin_bottom, margin_left)
+            }
+            _ => (Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0), Margin::Fixed(0))
+        }
+    }
+
+    fn get_color(&self, pair: i16, is_bold: bool) -> attr_t {
+        if *USE_COLOR.read().unwrap() {
+            attr_color(pair, is_bold)
+        } else {
+            attr_mono(pair, is_bold)
+        }
+    }
+
     pub fn resize(&mut self) {
-        let (_, max_x) = self.screen.getmaxyx();
+        let (max_y, max_x) = self.backend.get_maxyx();
+
+        self.start_y = match self.height {
+            Margin::Percent(100) => 0,
+            Margin::Percent(p) => min(self.start_y, max_y - p*max_y/100),
+            Margin::Fixed(rows) => min(self.start_y, max_y - rows),
+        };
 
         let height = self.height_in_rows();
 
@@ -356,10 +652,17 @@ impl Curses {
         };
 
         debug!("curses:resize: after, trbl: {}, {}, {}, {}", self.top, self.right, self.bottom, self.left);
+
+        // reallocate the double buffer; `front` is seeded with a cell value no real
+        // draw produces, so the next commit() redraws everything, including blanks
+        let (rows, cols) = (max_y.max(0) as usize, max_x.max(0) as usize);
+        let dirty = Cell { ch: '\0', attr: !0 };
+        self.front = vec![vec![dirty; cols]; rows];
+        self.back = vec![vec![Cell::default(); cols]; rows];
     }
 
     fn height_in_rows(&self) -> i32 {
-        let (max_y, _) = self.screen.getmaxyx();
+        let (max_y, _) = self.backend.get_maxyx();
         match self.height {
             Margin::Percent(100) => max_y,
             Margin::Percent(p) => min(max_y, p*max_y/100),
@@ -367,10 +670,9 @@ impl Curses {
         }
     }
 
-    pub fn mv(&self, y: i32, x: i32) {
-        self.screen.mv(y+self.top, x+self.left);
-        let (yy, xx) = self.screen.getyx();
-        debug!("curses:mv({}, {}); after: {}, {}, {}/{}", y, x, y + self.top, x + self.left, yy, xx);
+    pub fn mv(&mut self, y: i32, x: i32) {
+        self.cursor = (y+self.top, x+self.left);
+        debug!("curses:mv({}, {}); after: {}, {}", y, x, self.cursor.0, self.cursor.1);
     }
 
     pub fn get_maxyx(&self) -> (i32, i32) {
@@ -380,82 +682,223 @@ impl Curses {
     }
 
     pub fn getyx(&self) -> (i32, i32) {
-        let (y, x) = self.screen.getyx();
+        let (y, x) = self.cursor;
         (y-self.top, x-self.left)
     }
 
-    pub fn clrtoeol(&self) {
+    // write `text` into the back buffer at the cursor, advancing it
+    fn write_cells(&mut self, text: &str, attr: attr_t) {
+        let (y, mut x) = self.cursor;
+        for ch in text.chars() {
+            if y >= 0 && x >= 0 && (y as usize) < self.back.len() && (x as usize) < self.back[y as usize].len() {
+                self.back[y as usize][x as usize] = Cell { ch, attr };
+            }
+            x += 1;
+        }
+        self.cursor = (y, x);
+    }
+
+    pub fn clrtoeol(&mut self) {
         debug!("curses:clrtoeol();");
-        //self.screen.clrtoeol();
         let spaces = " ".repeat((self.right - self.bottom) as usize);
-        let (y, x) = self.screen.getyx();
-        self.screen.mv(y, 0);
-        printw(&spaces);
-        self.screen.mv(y, x);
+        let attr = self.cur_attr;
+        self.write_cells(&spaces, attr);
     }
 
-    pub fn erase(&self) {
+    pub fn erase(&mut self) {
         debug!("curses:erase(); top: {}, bottom:{}", self.top, self.bottom);
-        //self.screen.erase();
         let spaces = " ".repeat((self.right - self.bottom) as usize);
         for i in self.top..self.bottom {
-            self.screen.mv(i, 0);
-            printw(&spaces);
-            //self.screen.clrtoeol();
+            self.cursor = (i, 0);
+            self.write_cells(&spaces, 0);
         }
     }
 
-    pub fn cprint(&self, text: &str, pair: i16, is_bold: bool) {
+    pub fn cprint(&mut self, text: &str, pair: i16, is_bold: bool) {
         debug!("curses:addstr({:?});", text);
         let attr = self.get_color(pair, is_bold);
-        attron(attr);
-        addstr(text);
-        attroff(attr);
+        self.write_cells(text, attr);
     }
 
-    pub fn caddch(&self, ch: char, pair: i16, is_bold: bool) {
+    pub fn caddch(&mut self, ch: char, pair: i16, is_bold: bool) {
         debug!("curses:addstr(&{:?}.to_string());", ch);
         let attr = self.get_color(pair, is_bold);
-        attron(attr);
-        addstr(&ch.to_string()); // to support wide character
-        attroff(attr);
+        self.write_cells(&ch.to_string(), attr); // to support wide character
     }
 
-    pub fn printw(&self, text: &str) {
-        debug!("curses:printw({:?});", text);
-        printw(text);
+    // like cprint, but interprets embedded ANSI SGR escapes (`\x1B[...m`) in `text`
+    // instead of applying a single color pair to the whole string
+    pub fn cprint_ansi(&mut self, text: &str) {
+        debug!("curses:cprint_ansi({:?});", text);
+        let mut state = AnsiState::default();
+        let bytes = text.as_bytes();
+        let (len, mut i, mut run_start) = (bytes.len(), 0, 0);
+
+        while i < len {
+            if bytes[i] == 0x1B && i + 1 < len && bytes[i + 1] == b'[' {
+                if run_start < i {
+                    self.emit_ansi_run(&text[run_start..i], &state);
+                }
+
+                let seq_start = i + 2;
+                let mut j = seq_start;
+                while j < len && !(bytes[j] >= 0x40 && bytes[j] <= 0x7E) {
+                    j += 1;
+                }
+
+                if j < len && bytes[j] == b'm' {
+                    state.apply(&text[seq_start..j]);
+                }
+                // any other final byte (or a sequence that ran off the end) is
+                // simply dropped, which is what "strip unrecognized sequences" means
+
+                i = if j < len { j + 1 } else { len };
+                run_start = i;
+            } else {
+                i += 1;
+            }
+        }
+
+        if run_start < len {
+            self.emit_ansi_run(&text[run_start..], &state);
+        }
     }
 
-    pub fn close(&self) {
-        debug!("curses:close();");
-        self.erase();
-        self.mv(0, 0);
-        self.screen.refresh();
-        if self.height != Margin::Percent(100) {
-            putp(&tigetstr("smcup"));
-            refresh();
+    fn emit_ansi_run(&mut self, text: &str, state: &AnsiState) {
+        if text.is_empty() {
+            return;
         }
-        endwin();
-        delscreen(self.screen.0);
+
+        // resolve the -1 "unset" sentinels to the theme's fg/bg before swapping, so a
+        // bare reverse-video sequence (no explicit color codes) still has colors to swap
+        let resolved_fg = if state.fg == -1 { *FG.read().unwrap() } else { state.fg };
+        let resolved_bg = if state.bg == -1 { *BG.read().unwrap() } else { state.bg };
+        let (fg, bg) = if state.reverse { (resolved_bg, resolved_fg) } else { (resolved_fg, resolved_bg) };
+        let attr = get_color_pair(fg, bg)
+            | if state.bold { A_BOLD() } else { 0 }
+            | if state.underline { A_UNDERLINE() } else { 0 };
+
+        self.write_cells(text, attr);
     }
 
-    pub fn attr_on(&self, attr: attr_t) {
+    pub fn printw(&mut self, text: &str) {
+        debug!("curses:printw({:?});", text);
+        let attr = self.cur_attr;
+        self.write_cells(text, attr);
+    }
+
+    pub fn attr_on(&mut self, attr: attr_t) {
         if attr == 0 {
-            attrset(0);
+            self.cur_attr = 0;
         } else {
-            attron(attr);
+            self.cur_attr |= attr;
+        }
+    }
+
+    // diff back against front and redraw only the cells that changed, coalescing
+    // adjacent dirty same-attr cells on a row into a single mv+print
+    pub fn commit(&mut self) {
+        debug!("curses:commit();");
+        for y in 0..self.back.len() {
+            let cols = self.back[y].len();
+            let mut x = 0;
+            while x < cols {
+                if self.back[y][x] == self.front[y][x] {
+                    x += 1;
+                    continue;
+                }
+
+                let attr = self.back[y][x].attr;
+                let start = x;
+                let mut run = String::new();
+                while x < cols && self.back[y][x] != self.front[y][x] && self.back[y][x].attr == attr {
+                    run.push(self.back[y][x].ch);
+                    x += 1;
+                }
+
+                self.backend.mv(y as i32, start as i32);
+                self.backend.attr_on(attr);
+                self.backend.print(&run);
+                self.backend.attr_off(attr);
+            }
         }
+
+        self.front = self.back.clone();
+        self.backend.refresh();
     }
 
-    pub fn refresh(&self) {
+    pub fn refresh(&mut self) {
         debug!("curses:refresh();");
-        self.screen.refresh();
+        self.commit();
+    }
+}
+
+// running SGR state while parsing a `--ansi` item's embedded escape sequences
+#[derive(Clone, Copy, Debug)]
+struct AnsiState {
+    fg: i16,
+    bg: i16,
+    bold: bool,
+    underline: bool,
+    reverse: bool,
+}
+
+impl Default for AnsiState {
+    fn default() -> Self {
+        AnsiState { fg: -1, bg: -1, bold: false, underline: false, reverse: false }
     }
 }
 
-// use default if x is COLOR_UNDEFINED, else use x
-fn shadow(default: i16, x: i16) -> i16 {
-    if x == COLOR_UNDEFINED { default } else { x }
+impl AnsiState {
+    // apply the parameters of one `\x1B[...m` sequence (without the ESC, `[` or `m`)
+    fn apply(&mut self, params: &str) {
+        if params.is_empty() {
+            *self = AnsiState::default();
+            return;
+        }
+
+        let codes: Vec<i32> = params.split(';').map(|p| p.parse().unwrap_or(0)).collect();
+        let mut idx = 0;
+        while idx < codes.len() {
+            match codes[idx] {
+                0 => *self = AnsiState::default(),
+                1 => self.bold = true,
+                4 => self.underline = true,
+                7 => self.reverse = true,
+                22 => self.bold = false,
+                24 => self.underline = false,
+                27 => self.reverse = false,
+                39 => self.fg = -1,
+                49 => self.bg = -1,
+                30..=37 => self.fg = (codes[idx] - 30) as i16,
+                40..=47 => self.bg = (codes[idx] - 40) as i16,
+                90..=97 => self.fg = (codes[idx] - 90 + 8) as i16,
+                100..=107 => self.bg = (codes[idx] - 100 + 8) as i16,
+                38 | 48 => {
+                    let is_fg = codes[idx] == 38;
+                    match codes.get(idx + 1) {
+                        Some(5) => {
+                            if let Some(&n) = codes.get(idx + 2) {
+                                let color = n as i16;
+                                if is_fg { self.fg = color; } else { self.bg = color; }
+                                idx += 2;
+                            }
+                        }
+                        Some(2) => {
+                            if let (Some(&r), Some(&g), Some(&b)) = (codes.get(idx + 2), codes.get(idx + 3), codes.get(idx + 4)) {
+                                let color = resolve_color(Color::Rgb(r as u8, g as u8, b as u8));
+                                if is_fg { self.fg = color; } else { self.bg = color; }
+                                idx += 4;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+            idx += 1;
+        }
+    }
 }
 
 
@@ -487,44 +930,110 @@ fn attr_mono(pair: i16, is_bold: bool) -> attr_t {
 const COLOR_DEFAULT: i16 = -1;
 const COLOR_UNDEFINED: i16 = -2;
 
+// a single color role's value: an ncurses palette index, an RGB triple resolved down
+// to whatever the terminal can render, or the terminal's own default
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Color {
+    Undefined,
+    Default,
+    Palette(i16),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    fn parse(s: &str) -> Color {
+        match s.to_ascii_lowercase().as_str() {
+            "default" => return Color::Default,
+            "black"   => return Color::Palette(COLOR_BLACK),
+            "red"     => return Color::Palette(COLOR_RED),
+            "green"   => return Color::Palette(COLOR_GREEN),
+            "yellow"  => return Color::Palette(COLOR_YELLOW),
+            "blue"    => return Color::Palette(COLOR_BLUE),
+            "magenta" => return Color::Palette(COLOR_MAGENTA),
+            "cyan"    => return Color::Palette(COLOR_CYAN),
+            "white"   => return Color::Palette(COLOR_WHITE),
+            _ => {}
+        }
+
+        if let Some(hex) = s.strip_prefix('#') {
+            let expand = |c: char| u8::from_str_radix(&c.to_string().repeat(2), 16).unwrap_or(0);
+            return match hex.len() {
+                6 => {
+                    let byte = |i: usize| u8::from_str_radix(&hex[i..i+2], 16).unwrap_or(0);
+                    Color::Rgb(byte(0), byte(2), byte(4))
+                }
+                3 => {
+                    let chars: Vec<char> = hex.chars().collect();
+                    Color::Rgb(expand(chars[0]), expand(chars[1]), expand(chars[2]))
+                }
+                _ => Color::Undefined,
+            };
+        }
+
+        match s.parse::<i16>() {
+            Ok(n) => Color::Palette(n),
+            Err(_) => Color::Undefined,
+        }
+    }
+}
+
+// an ordered list of candidate colors for a role, e.g. parsed from
+// `current_bg:#923456/236/magenta`; a fixed-size array rather than a Vec so the
+// built-in theme tables below can stay `const`
+const MAX_FALLBACKS: usize = 4;
+pub type ColorChain = [Color; MAX_FALLBACKS];
+
+const fn chain1(color: Color) -> ColorChain {
+    [color, Color::Undefined, Color::Undefined, Color::Undefined]
+}
+
+fn parse_chain(s: &str) -> ColorChain {
+    let mut chain = [Color::Undefined; MAX_FALLBACKS];
+    for (slot, candidate) in chain.iter_mut().zip(s.split('/')) {
+        *slot = Color::parse(candidate);
+    }
+    chain
+}
+
 #[derive(Clone, Debug)]
 pub struct ColorTheme {
     use_default: bool,
 
-    fg: i16, // text fg
-    bg: i16, // text bg
-    matched: i16,
-    matched_bg: i16,
-    current: i16,
-    current_bg: i16,
-    current_match: i16,
-    current_match_bg: i16,
-    spinner: i16,
-    info: i16,
-    prompt: i16,
-    cursor: i16,
-    selected: i16,
-    header: i16,
+    fg: ColorChain, // text fg
+    bg: ColorChain, // text bg
+    matched: ColorChain,
+    matched_bg: ColorChain,
+    current: ColorChain,
+    current_bg: ColorChain,
+    current_match: ColorChain,
+    current_match_bg: ColorChain,
+    spinner: ColorChain,
+    info: ColorChain,
+    prompt: ColorChain,
+    cursor: ColorChain,
+    selected: ColorChain,
+    header: ColorChain,
 }
 
 impl ColorTheme {
     pub fn new() -> Self {
+        let undefined = chain1(Color::Undefined);
         ColorTheme {
             use_default:  true,
-            fg:               COLOR_UNDEFINED,
-            bg:               COLOR_UNDEFINED,
-            matched:          COLOR_UNDEFINED,
-            matched_bg:       COLOR_UNDEFINED,
-            current:          COLOR_UNDEFINED,
-            current_bg:       COLOR_UNDEFINED,
-            current_match:    COLOR_UNDEFINED,
-            current_match_bg: COLOR_UNDEFINED,
-            spinner:          COLOR_UNDEFINED,
-            info:             COLOR_UNDEFINED,
-            prompt:           COLOR_UNDEFINED,
-            cursor:           COLOR_UNDEFINED,
-            selected:         COLOR_UNDEFINED,
-            header:           COLOR_UNDEFINED,
+            fg:               undefined,
+            bg:               undefined,
+            matched:          undefined,
+            matched_bg:       undefined,
+            current:          undefined,
+            current_bg:       undefined,
+            current_match:    undefined,
+            current_match_bg: undefined,
+            spinner:          undefined,
+            info:             undefined,
+            prompt:           undefined,
+            cursor:           undefined,
+            selected:         undefined,
+            header:           undefined,
         }
     }
 
@@ -542,95 +1051,348 @@ impl ColorTheme {
             }
 
             match color[0] {
-                "fg"               => theme.fg = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "bg"               => theme.bg = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "matched"          => theme.matched = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "matched_bg"       => theme.matched_bg = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "current"          => theme.current = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "current_bg"       => theme.current_bg = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "current_match"    => theme.current_match = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "current_match_bg" => theme.current_match_bg = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "spinner"          => theme.spinner = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "info"             => theme.info = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "prompt"           => theme.prompt = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "cursor"           => theme.cursor = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "selected"         => theme.selected = color[1].parse().unwrap_or(COLOR_UNDEFINED),
-                "header"           => theme.header = color[1].parse().unwrap_or(COLOR_UNDEFINED),
+                "fg"               => theme.fg = parse_chain(color[1]),
+                "bg"               => theme.bg = parse_chain(color[1]),
+                "matched"          => theme.matched = parse_chain(color[1]),
+                "matched_bg"       => theme.matched_bg = parse_chain(color[1]),
+                "current"          => theme.current = parse_chain(color[1]),
+                "current_bg"       => theme.current_bg = parse_chain(color[1]),
+                "current_match"    => theme.current_match = parse_chain(color[1]),
+                "current_match_bg" => theme.current_match_bg = parse_chain(color[1]),
+                "spinner"          => theme.spinner = parse_chain(color[1]),
+                "info"             => theme.info = parse_chain(color[1]),
+                "prompt"           => theme.prompt = parse_chain(color[1]),
+                "cursor"           => theme.cursor = parse_chain(color[1]),
+                "selected"         => theme.selected = parse_chain(color[1]),
+                "header"           => theme.header = parse_chain(color[1]),
                 _ => {}
             }
         }
         theme
     }
+
+    // load a named theme out of a TOML config file, falling back to
+    // `$XDG_CONFIG_HOME/skim/config.toml` when no explicit path is given
+    pub fn from_config_file(path: Option<&str>) -> Self {
+        let path = path.map(PathBuf::from).unwrap_or_else(Self::default_config_path);
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => return DARK256.clone(),
+        };
+
+        let doc: toml::Value = match contents.parse() {
+            Ok(doc) => doc,
+            Err(_) => return DARK256.clone(),
+        };
+
+        let active = doc.get("theme").and_then(|v| v.as_str()).unwrap_or("dark");
+        let mut theme = Self::built_in(active);
+
+        if let Some(table) = doc.get("themes").and_then(|themes| themes.get(active)).and_then(|t| t.as_table()) {
+            Self::merge_table(&mut theme, table);
+        }
+
+        theme
+    }
+
+    fn default_config_path() -> PathBuf {
+        let config_home = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string())).join(".config"));
+        config_home.join("skim").join("config.toml")
+    }
+
+    fn built_in(name: &str) -> Self {
+        match name {
+            "molokai" => MONOKAI256.clone(),
+            "light" => LIGHT256.clone(),
+            "16"  => DEFAULT16.clone(),
+            "dark" | _ => DARK256.clone(),
+        }
+    }
+
+    fn merge_table(theme: &mut ColorTheme, table: &toml::value::Table) {
+        for (key, value) in table {
+            let chain = match value {
+                toml::Value::Integer(n) => chain1(Color::Palette(*n as i16)),
+                toml::Value::String(s) => parse_chain(s),
+                _ => continue,
+            };
+
+            match key.as_str() {
+                "fg"               => theme.fg = chain,
+                "bg"               => theme.bg = chain,
+                "matched"          => theme.matched = chain,
+                "matched_bg"       => theme.matched_bg = chain,
+                "current"          => theme.current = chain,
+                "current_bg"       => theme.current_bg = chain,
+                "current_match"    => theme.current_match = chain,
+                "current_match_bg" => theme.current_match_bg = chain,
+                "spinner"          => theme.spinner = chain,
+                "info"             => theme.info = chain,
+                "prompt"           => theme.prompt = chain,
+                "cursor"           => theme.cursor = chain,
+                "selected"         => theme.selected = chain,
+                "header"           => theme.header = chain,
+                _ => {}
+            }
+        }
+    }
 }
 
 const DEFAULT16: ColorTheme = ColorTheme {
     use_default:   true,
-    fg:               15,
-    bg:               0,
-    matched:          COLOR_GREEN,
-    matched_bg:       COLOR_BLACK,
-    current:          COLOR_YELLOW,
-    current_bg:       COLOR_BLACK,
-    current_match:    COLOR_GREEN,
-    current_match_bg: COLOR_BLACK,
-    spinner:          COLOR_GREEN,
-    info:             COLOR_WHITE,
-    prompt:           COLOR_BLUE,
-    cursor:           COLOR_RED,
-    selected:         COLOR_MAGENTA,
-    header:           COLOR_CYAN,
+    fg:               chain1(Color::Palette(15)),
+    bg:               chain1(Color::Palette(0)),
+    matched:          chain1(Color::Palette(COLOR_GREEN)),
+    matched_bg:       chain1(Color::Palette(COLOR_BLACK)),
+    current:          chain1(Color::Palette(COLOR_YELLOW)),
+    current_bg:       chain1(Color::Palette(COLOR_BLACK)),
+    current_match:    chain1(Color::Palette(COLOR_GREEN)),
+    current_match_bg: chain1(Color::Palette(COLOR_BLACK)),
+    spinner:          chain1(Color::Palette(COLOR_GREEN)),
+    info:             chain1(Color::Palette(COLOR_WHITE)),
+    prompt:           chain1(Color::Palette(COLOR_BLUE)),
+    cursor:           chain1(Color::Palette(COLOR_RED)),
+    selected:         chain1(Color::Palette(COLOR_MAGENTA)),
+    header:           chain1(Color::Palette(COLOR_CYAN)),
 };
 
 const DARK256: ColorTheme = ColorTheme {
     use_default:   true,
-    fg:               15,
-    bg:               0,
-    matched:          108,
-    matched_bg:       0,
-    current:          254,
-    current_bg:       236,
-    current_match:    151,
-    current_match_bg: 236,
-    spinner:          148,
-    info:             144,
-    prompt:           110,
-    cursor:           161,
-    selected:         168,
-    header:           109,
+    fg:               chain1(Color::Palette(15)),
+    bg:               chain1(Color::Palette(0)),
+    matched:          chain1(Color::Palette(108)),
+    matched_bg:       chain1(Color::Palette(0)),
+    current:          chain1(Color::Palette(254)),
+    current_bg:       chain1(Color::Palette(236)),
+    current_match:    chain1(Color::Palette(151)),
+    current_match_bg: chain1(Color::Palette(236)),
+    spinner:          chain1(Color::Palette(148)),
+    info:             chain1(Color::Palette(144)),
+    prompt:           chain1(Color::Palette(110)),
+    cursor:           chain1(Color::Palette(161)),
+    selected:         chain1(Color::Palette(168)),
+    header:           chain1(Color::Palette(109)),
 };
 
 const MONOKAI256: ColorTheme = ColorTheme {
     use_default:   true,
-    fg:               252,
-    bg:               234,
-    matched:          234,
-    matched_bg:       186,
-    current:          254,
-    current_bg:       236,
-    current_match:    234,
-    current_match_bg: 186,
-    spinner:          148,
-    info:             144,
-    prompt:           110,
-    cursor:           161,
-    selected:         168,
-    header:           109,
+    fg:               chain1(Color::Palette(252)),
+    bg:               chain1(Color::Palette(234)),
+    matched:          chain1(Color::Palette(234)),
+    matched_bg:       chain1(Color::Palette(186)),
+    current:          chain1(Color::Palette(254)),
+    current_bg:       chain1(Color::Palette(236)),
+    current_match:    chain1(Color::Palette(234)),
+    current_match_bg: chain1(Color::Palette(186)),
+    spinner:          chain1(Color::Palette(148)),
+    info:             chain1(Color::Palette(144)),
+    prompt:           chain1(Color::Palette(110)),
+    cursor:           chain1(Color::Palette(161)),
+    selected:         chain1(Color::Palette(168)),
+    header:           chain1(Color::Palette(109)),
 };
 
 const LIGHT256: ColorTheme = ColorTheme {
     use_default:   true,
-    fg:               15,
-    bg:               0,
-    matched:          0,
-    matched_bg:       220,
-    current:          237,
-    current_bg:       251,
-    current_match:    66,
-    current_match_bg: 251,
-    spinner:          65,
-    info:             101,
-    prompt:           25,
-    cursor:           161,
-    selected:         168,
-    header:           31,
+    fg:               chain1(Color::Palette(15)),
+    bg:               chain1(Color::Palette(0)),
+    matched:          chain1(Color::Palette(0)),
+    matched_bg:       chain1(Color::Palette(220)),
+    current:          chain1(Color::Palette(237)),
+    current_bg:       chain1(Color::Palette(251)),
+    current_match:    chain1(Color::Palette(66)),
+    current_match_bg: chain1(Color::Palette(251)),
+    spinner:          chain1(Color::Palette(65)),
+    info:             chain1(Color::Palette(101)),
+    prompt:           chain1(Color::Palette(25)),
+    cursor:           chain1(Color::Palette(161)),
+    selected:         chain1(Color::Palette(168)),
+    header:           chain1(Color::Palette(31)),
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_margin_string_parses_percent_and_fixed() {
+        assert_eq!(Curses::<VirtualBackend>::parse_margin_string("50%"), Margin::Percent(50));
+        assert_eq!(Curses::<VirtualBackend>::parse_margin_string("3"), Margin::Fixed(3));
+    }
+
+    #[test]
+    fn parse_margin_expands_shorthand_forms() {
+        assert_eq!(
+            Curses::<VirtualBackend>::parse_margin("2"),
+            (Margin::Fixed(2), Margin::Fixed(2), Margin::Fixed(2), Margin::Fixed(2))
+        );
+        assert_eq!(
+            Curses::<VirtualBackend>::parse_margin("1,2"),
+            (Margin::Fixed(1), Margin::Fixed(2), Margin::Fixed(1), Margin::Fixed(2))
+        );
+        assert_eq!(
+            Curses::<VirtualBackend>::parse_margin("1,2,3,4"),
+            (Margin::Fixed(1), Margin::Fixed(2), Margin::Fixed(3), Margin::Fixed(4))
+        );
+    }
+
+    #[test]
+    fn resize_computes_margins_against_backend_size() {
+        let backend = VirtualBackend::new(20, 40);
+        let curses = Curses::with_backend(backend, Some("2,4"), None);
+        assert_eq!(curses.get_maxyx(), (16, 32));
+    }
+
+    #[test]
+    fn attr_color_sets_color_pair_and_bold() {
+        assert_eq!(attr_color(COLOR_MATCHED, false), COLOR_PAIR(COLOR_MATCHED));
+        assert_eq!(attr_color(COLOR_MATCHED, true), COLOR_PAIR(COLOR_MATCHED) | A_BOLD());
+        assert_eq!(attr_color(COLOR_NORMAL, false), 0);
+    }
+
+    #[test]
+    fn attr_mono_falls_back_to_distinguishing_attributes() {
+        assert_eq!(attr_mono(COLOR_MATCHED, false), A_UNDERLINE());
+        assert_eq!(attr_mono(COLOR_CURRENT_MATCH, false), A_UNDERLINE() | A_REVERSE());
+        assert_eq!(attr_mono(COLOR_NORMAL, true), A_REVERSE() | A_BOLD());
+    }
+
+    #[test]
+    fn commit_writes_the_first_frame_in_full() {
+        let backend = VirtualBackend::new(2, 4);
+        let mut curses = Curses::with_backend(backend, None, None);
+        curses.mv(0, 0);
+        curses.printw("ab");
+        curses.commit();
+
+        assert_eq!(curses.backend.row(0), "ab  ");
+    }
+
+    #[test]
+    fn commit_skips_cells_that_did_not_change() {
+        let backend = VirtualBackend::new(2, 4);
+        let mut curses = Curses::with_backend(backend, None, None);
+        curses.mv(0, 0);
+        curses.printw("ab");
+        curses.commit();
+
+        // poke the backend directly where `back` and `front` already agree; a diffing
+        // commit() has no reason to touch it, so it should survive a second commit
+        curses.backend.grid[0][3] = Cell { ch: 'Z', attr: 0 };
+        curses.mv(0, 0);
+        curses.printw("ac");
+        curses.commit();
+
+        assert_eq!(curses.backend.row(0), "ac Z");
+        assert_eq!(curses.backend.cell(0, 3).ch, 'Z');
+    }
+
+    #[test]
+    fn downsample_256_rounds_the_gray_ramp_step() {
+        // luma = 13 -> (13-8)/10 rounds up to step 1, not down to step 0
+        assert_eq!(downsample_256(13, 13, 13), 233);
+    }
+
+    #[test]
+    fn downsample_256_picks_the_closer_of_cube_and_gray_ramp() {
+        assert_eq!(downsample_256(255, 0, 0), 16 + 36 * 5); // pure red: cube corner
+        assert_eq!(downsample_256(128, 128, 128), 244); // mid gray: gray ramp
+    }
+
+    #[test]
+    fn resolve_color_passes_through_non_rgb_variants() {
+        assert_eq!(resolve_color(Color::Undefined), COLOR_UNDEFINED);
+        assert_eq!(resolve_color(Color::Default), COLOR_DEFAULT);
+        assert_eq!(resolve_color(Color::Palette(5)), 5);
+    }
+
+    #[test]
+    fn parse_chain_splits_fallback_candidates() {
+        let chain = parse_chain("default/236/magenta");
+        assert_eq!(chain[0], Color::Default);
+        assert_eq!(chain[1], Color::Palette(236));
+        assert_eq!(chain[2], Color::Palette(COLOR_MAGENTA));
+        assert_eq!(chain[3], Color::Undefined);
+    }
+
+    #[test]
+    fn resolve_chain_falls_back_to_base_when_theme_chain_is_unset() {
+        let base = chain1(Color::Default);
+        let theme = [Color::Undefined; MAX_FALLBACKS];
+        assert_eq!(resolve_chain(base, theme), COLOR_DEFAULT);
+    }
+
+    #[test]
+    fn resolve_chain_uses_theme_when_base_chain_is_unset() {
+        let base = chain1(Color::Undefined);
+        let theme = chain1(Color::Default);
+        assert_eq!(resolve_chain(base, theme), COLOR_DEFAULT);
+    }
+
+    #[test]
+    fn merge_table_overrides_integer_and_string_roles() {
+        let mut theme = DARK256.clone();
+        let mut table = toml::value::Table::new();
+        table.insert("fg".to_string(), toml::Value::Integer(7));
+        table.insert("current_bg".to_string(), toml::Value::String("magenta/236".to_string()));
+
+        ColorTheme::merge_table(&mut theme, &table);
+
+        assert_eq!(theme.fg, chain1(Color::Palette(7)));
+        assert_eq!(theme.current_bg, parse_chain("magenta/236"));
+    }
+
+    #[test]
+    fn from_config_file_selects_active_theme_and_merges_overrides() {
+        let path = env::temp_dir().join(format!("skim-test-config-{}.toml", std::process::id()));
+        fs::write(&path, "theme = \"molokai\"\n\n[themes.molokai]\nfg = 15\n").unwrap();
+
+        let theme = ColorTheme::from_config_file(Some(path.to_str().unwrap()));
+        fs::remove_file(&path).ok();
+
+        assert_eq!(theme.fg, chain1(Color::Palette(15)));
+        // untouched roles still come from the molokai built-in base
+        assert_eq!(theme.bg, MONOKAI256.bg);
+    }
+
+    #[test]
+    fn ansi_state_apply_sets_indexed_colors_and_attributes() {
+        let mut state = AnsiState::default();
+        state.apply("1;4;31;42");
+        assert!(state.bold);
+        assert!(state.underline);
+        assert_eq!(state.fg, 1);
+        assert_eq!(state.bg, 2);
+
+        state.apply("90;100");
+        assert_eq!(state.fg, 8);
+        assert_eq!(state.bg, 8);
+
+        state.apply("7");
+        assert!(state.reverse);
+
+        state.apply("0");
+        assert_eq!(state.fg, -1);
+        assert!(!state.bold);
+    }
+
+    #[test]
+    fn ansi_state_apply_parses_256_color_form() {
+        let mut state = AnsiState::default();
+        state.apply("38;5;208;48;5;16");
+        assert_eq!(state.fg, 208);
+        assert_eq!(state.bg, 16);
+    }
+
+    #[test]
+    fn ansi_state_apply_resets_explicit_fg_bg() {
+        let mut state = AnsiState::default();
+        state.apply("31;41");
+        state.apply("39;49");
+        assert_eq!(state.fg, -1);
+        assert_eq!(state.bg, -1);
+    }
+}